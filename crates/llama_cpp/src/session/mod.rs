@@ -0,0 +1,229 @@
+//! Implements [`LlamaSession`], an evaluation session for a loaded `llama.cpp` context.
+
+pub mod params;
+
+use std::ffi::CString;
+use std::os::raw::c_char;
+use std::ptr::NonNull;
+
+use llama_cpp_sys::{
+    ggml_threadpool, ggml_threadpool_free, ggml_threadpool_new, llama_attach_threadpool,
+    llama_context, llama_free, llama_model, llama_model_meta_val_str,
+    llama_new_context_with_model, llama_pooling_type, llama_pooling_type_LLAMA_POOLING_TYPE_CLS,
+    llama_pooling_type_LLAMA_POOLING_TYPE_LAST, llama_pooling_type_LLAMA_POOLING_TYPE_NONE,
+    llama_set_embeddings,
+};
+
+pub use params::{PoolingType, SessionParams, ThreadpoolParams};
+
+/// An evaluation session for a llama.cpp context, wrapping a loaded model.
+pub struct LlamaSession {
+    pub(crate) ctx: NonNull<llama_context>,
+    pub(crate) params: SessionParams,
+    threadpool: Option<NonNull<ggml_threadpool>>,
+}
+
+impl LlamaSession {
+    /// Creates a new session on `model`, applying `params`.
+    ///
+    /// Resolves [`PoolingType::Unspecified`] against `model`'s own GGUF metadata (see
+    /// [`resolve_pooling_type`]) before building the underlying `llama_context` from `params`,
+    /// then performs the steps that need a live context to take effect: attaching the ggml
+    /// threadpool described by [`SessionParams::threadpool`] (see [`Self::init_threadpool`]).
+    ///
+    /// Returns `None` if `llama_new_context_with_model` fails to allocate a context.
+    pub fn new(model: *mut llama_model, mut params: SessionParams) -> Option<Self> {
+        params.pooling = resolve_pooling_type(model, params.pooling);
+
+        let cparams = (&params).into();
+
+        let ctx = unsafe {
+            // SAFETY: `model` is a valid, loaded model; `cparams` is a valid context config.
+            llama_new_context_with_model(model, cparams)
+        };
+        let ctx = NonNull::new(ctx)?;
+
+        let mut session = Self {
+            ctx,
+            params,
+            threadpool: None,
+        };
+
+        session.init_threadpool();
+
+        Some(session)
+    }
+
+    /// Enables or disables embeddings output for this session at runtime, without rebuilding
+    /// the context (and thus without losing any KV cache state).
+    ///
+    /// The initial value comes from [`SessionParams::embedding`]; this lets callers flip a
+    /// single loaded context between generation and embedding extraction on the fly.
+    pub fn set_embeddings(&mut self, enabled: bool) {
+        self.params.embedding = enabled;
+
+        unsafe {
+            // SAFETY: `self.ctx` is a valid, live context for the duration of `self`.
+            llama_set_embeddings(self.ctx.as_ptr(), enabled);
+        }
+    }
+
+    /// Builds the ggml threadpool described by [`SessionParams::threadpool`], if any, and
+    /// attaches it to this session's context so that both generation and batch processing
+    /// threads run on it, instead of ggml spawning a fresh pool per decode.
+    pub(crate) fn init_threadpool(&mut self) {
+        let Some(threadpool_params) = self.params.threadpool.as_ref() else {
+            return;
+        };
+
+        let mut raw_params = threadpool_params.into();
+
+        let threadpool = unsafe {
+            // SAFETY: `raw_params` is a valid, fully-initialized `ggml_threadpool_params`.
+            ggml_threadpool_new(&mut raw_params)
+        };
+
+        let Some(threadpool) = NonNull::new(threadpool) else {
+            return;
+        };
+
+        unsafe {
+            // SAFETY: `self.ctx` and `threadpool` are both live for the duration of this call.
+            llama_attach_threadpool(self.ctx.as_ptr(), threadpool.as_ptr(), std::ptr::null_mut());
+        }
+
+        self.threadpool = Some(threadpool);
+    }
+}
+
+/// Resolves the effective pooling mode for a model, given what the caller asked for in
+/// [`SessionParams::pooling`].
+///
+/// When the caller left it as [`PoolingType::Unspecified`], this reads the model's own
+/// `{arch}.pooling_type` GGUF metadata key (written by conversion scripts as a `uint32`,
+/// matching the numeric `enum llama_pooling_type` it's loaded into, not a string like `"CLS"`)
+/// rather than deferring blindly to the C default, so a model that expects e.g. CLS pooling
+/// doesn't silently get Mean pooling.
+pub(crate) fn resolve_pooling_type(model: *mut llama_model, requested: PoolingType) -> PoolingType {
+    if !matches!(requested, PoolingType::Unspecified) {
+        return requested;
+    }
+
+    let Some(arch) = meta_val_str(model, "general.architecture") else {
+        return PoolingType::Mean;
+    };
+
+    let Some(raw) = meta_val_str(model, &format!("{arch}.pooling_type")) else {
+        return PoolingType::Mean;
+    };
+
+    pooling_type_from_metadata(&raw)
+}
+
+/// Maps a raw `{arch}.pooling_type` GGUF metadata value to a [`PoolingType`].
+///
+/// The value is the decimal string representation of the numeric `enum llama_pooling_type`
+/// (conversion scripts write it as a `uint32`, not a string literal like `"CLS"`). `MEAN`,
+/// `UNSPECIFIED`, anything unrecognized, and anything unparseable all fall back to the most
+/// common default.
+fn pooling_type_from_metadata(raw: &str) -> PoolingType {
+    #![allow(non_upper_case_globals)]
+
+    match raw.parse::<llama_pooling_type>() {
+        Ok(llama_pooling_type_LLAMA_POOLING_TYPE_NONE) => PoolingType::None,
+        Ok(llama_pooling_type_LLAMA_POOLING_TYPE_CLS) => PoolingType::Cls,
+        Ok(llama_pooling_type_LLAMA_POOLING_TYPE_LAST) => PoolingType::Last,
+        Ok(_) | Err(_) => PoolingType::Mean,
+    }
+}
+
+/// Reads a string-valued GGUF metadata key off `model`, if present.
+fn meta_val_str(model: *mut llama_model, key: &str) -> Option<String> {
+    let key = CString::new(key).ok()?;
+    let mut buf_size = 128usize;
+
+    // `llama_model_meta_val_str` is snprintf-style: it returns the length the value actually
+    // needs, which can exceed `buf_size`. Retry with a right-sized buffer rather than silently
+    // keeping the truncated (and zero-padded) first attempt.
+    loop {
+        let mut buf = vec![0u8; buf_size];
+
+        let len = unsafe {
+            // SAFETY: `model` is a valid, loaded model; `buf` is `buf_size` bytes long.
+            llama_model_meta_val_str(model, key.as_ptr(), buf.as_mut_ptr() as *mut c_char, buf.len())
+        };
+
+        if len < 0 {
+            return None;
+        }
+        let len = len as usize;
+
+        if len >= buf_size {
+            buf_size = len + 1;
+            continue;
+        }
+
+        buf.truncate(len);
+        return String::from_utf8(buf).ok();
+    }
+}
+
+impl Drop for LlamaSession {
+    fn drop(&mut self) {
+        if let Some(threadpool) = self.threadpool.take() {
+            unsafe {
+                // SAFETY: `threadpool` was created by `init_threadpool` and is only ever
+                // attached to this session's own context.
+                ggml_threadpool_free(threadpool.as_ptr());
+            }
+        }
+
+        unsafe {
+            // SAFETY: `self.ctx` was created by `Self::new` and is owned exclusively by
+            // this session; nothing else can be holding a reference to it past this point.
+            llama_free(self.ctx.as_ptr());
+        }
+    }
+}
+
+#[cfg(test)]
+mod pooling_type_from_metadata_tests {
+    use super::*;
+
+    #[test]
+    fn numeric_none_maps_to_none() {
+        let raw = llama_pooling_type_LLAMA_POOLING_TYPE_NONE.to_string();
+
+        assert!(matches!(pooling_type_from_metadata(&raw), PoolingType::None));
+    }
+
+    #[test]
+    fn numeric_cls_maps_to_cls() {
+        let raw = llama_pooling_type_LLAMA_POOLING_TYPE_CLS.to_string();
+
+        assert!(matches!(pooling_type_from_metadata(&raw), PoolingType::Cls));
+    }
+
+    #[test]
+    fn numeric_last_maps_to_last() {
+        let raw = llama_pooling_type_LLAMA_POOLING_TYPE_LAST.to_string();
+
+        assert!(matches!(pooling_type_from_metadata(&raw), PoolingType::Last));
+    }
+
+    #[test]
+    fn string_literal_is_not_mistaken_for_a_numeric_value() {
+        // Real GGUF metadata never contains this (the key is a `uint32`); a pooling name
+        // written as a string must fall back to the default instead of being silently misread.
+        assert!(matches!(
+            pooling_type_from_metadata("CLS"),
+            PoolingType::Mean
+        ));
+    }
+
+    #[test]
+    fn unrecognized_and_unparseable_values_fall_back_to_mean() {
+        assert!(matches!(pooling_type_from_metadata("9999"), PoolingType::Mean));
+        assert!(matches!(pooling_type_from_metadata(""), PoolingType::Mean));
+    }
+}
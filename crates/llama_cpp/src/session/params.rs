@@ -1,11 +1,19 @@
 //! Implements [`SessionParams`], which configures a [`crate::LlamaSession`]
 
+use std::os::raw::c_void;
 use std::ptr::null_mut;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 use llama_cpp_sys::{
-    ggml_type, llama_context_default_params, llama_context_params, llama_pooling_type,
-    llama_pooling_type_LLAMA_POOLING_TYPE_CLS, llama_pooling_type_LLAMA_POOLING_TYPE_MEAN,
+    ggml_threadpool_params, ggml_type, llama_attention_type,
+    llama_attention_type_LLAMA_ATTENTION_TYPE_CAUSAL,
+    llama_attention_type_LLAMA_ATTENTION_TYPE_NON_CAUSAL,
+    llama_attention_type_LLAMA_ATTENTION_TYPE_UNSPECIFIED, llama_context_default_params,
+    llama_context_params, llama_pooling_type, llama_pooling_type_LLAMA_POOLING_TYPE_CLS,
+    llama_pooling_type_LLAMA_POOLING_TYPE_LAST, llama_pooling_type_LLAMA_POOLING_TYPE_MEAN,
     llama_pooling_type_LLAMA_POOLING_TYPE_NONE, llama_pooling_type_LLAMA_POOLING_TYPE_UNSPECIFIED,
+    GGML_MAX_N_THREADS,
 };
 
 /// whether to pool (sum) embedding results by sequence id (ignored if no pooling layer)
@@ -19,6 +27,8 @@ pub enum PoolingType {
     Mean,
     /// TODO lookup what this does
     Cls,
+    /// Pool from the embedding of the final non-padding token of the sequence.
+    Last,
 }
 
 impl From<PoolingType> for llama_pooling_type {
@@ -28,6 +38,7 @@ impl From<PoolingType> for llama_pooling_type {
             PoolingType::None => llama_pooling_type_LLAMA_POOLING_TYPE_NONE,
             PoolingType::Mean => llama_pooling_type_LLAMA_POOLING_TYPE_MEAN,
             PoolingType::Cls => llama_pooling_type_LLAMA_POOLING_TYPE_CLS,
+            PoolingType::Last => llama_pooling_type_LLAMA_POOLING_TYPE_LAST,
         }
     }
 }
@@ -40,6 +51,41 @@ impl From<llama_pooling_type> for PoolingType {
             llama_pooling_type_LLAMA_POOLING_TYPE_NONE => PoolingType::None,
             llama_pooling_type_LLAMA_POOLING_TYPE_MEAN => PoolingType::Mean,
             llama_pooling_type_LLAMA_POOLING_TYPE_CLS => PoolingType::Cls,
+            llama_pooling_type_LLAMA_POOLING_TYPE_LAST => PoolingType::Last,
+            _ => unimplemented!(),
+        }
+    }
+}
+
+/// whether attention is causal (each token attends only to previous tokens) or non-causal
+/// (tokens may attend bidirectionally), used e.g. to extract embeddings from causal models
+#[derive(Clone, Copy, Debug)]
+pub enum AttentionType {
+    /// Unspecified, defer to the model's default.
+    Unspecified,
+    /// Causal attention, each token only attends to previous tokens.
+    Causal,
+    /// Non-causal (bidirectional) attention.
+    NonCausal,
+}
+
+impl From<AttentionType> for llama_attention_type {
+    fn from(value: AttentionType) -> Self {
+        match value {
+            AttentionType::Unspecified => llama_attention_type_LLAMA_ATTENTION_TYPE_UNSPECIFIED,
+            AttentionType::Causal => llama_attention_type_LLAMA_ATTENTION_TYPE_CAUSAL,
+            AttentionType::NonCausal => llama_attention_type_LLAMA_ATTENTION_TYPE_NON_CAUSAL,
+        }
+    }
+}
+
+impl From<llama_attention_type> for AttentionType {
+    fn from(value: llama_attention_type) -> Self {
+        #![allow(non_upper_case_globals)]
+        match value {
+            llama_attention_type_LLAMA_ATTENTION_TYPE_UNSPECIFIED => AttentionType::Unspecified,
+            llama_attention_type_LLAMA_ATTENTION_TYPE_CAUSAL => AttentionType::Causal,
+            llama_attention_type_LLAMA_ATTENTION_TYPE_NON_CAUSAL => AttentionType::NonCausal,
             _ => unimplemented!(),
         }
     }
@@ -57,6 +103,10 @@ pub struct SessionParams {
     /// prompt processing maximum batch size
     pub n_batch: u32,
 
+    /// physical maximum batch size (the number of tokens actually processed per compute-graph
+    /// split), enables overlapping work across devices when using pipeline parallelism
+    pub n_ubatch: u32,
+
     /// number of threads to use for generation
     pub n_threads: u32,
 
@@ -104,8 +154,143 @@ pub struct SessionParams {
     /// whether to pool (sum) embedding results by sequence id (ignored if no pooling layer)
     pub pooling: PoolingType,
 
+    /// whether attention is causal or non-causal, [`AttentionType::Unspecified`] defers to the model
+    pub attention_type: AttentionType,
+
     /// defragment the KV cache if holes/size > thold, < 0 disabled (default)
     pub defrag_threshold: f32,
+
+    /// when set, polled before each batch during `llama_decode`; setting the flag to `true`
+    /// aborts the in-flight decode as soon as possible, letting callers cancel a runaway or
+    /// timed-out generation without killing the whole process
+    ///
+    /// # Lifetime contract
+    /// [`From<&SessionParams> for llama_context_params`](struct.llama_context_params.html) only
+    /// borrows this `Arc` to read its raw pointer into `abort_callback_data` — it does not keep
+    /// the `Arc` alive itself. Whatever builds the context (i.e. [`crate::LlamaSession::new`])
+    /// must retain this `SessionParams` (or another clone of this `Arc`) for at least as long as
+    /// the resulting context is alive, or `abort_callback_data` will dangle.
+    pub abort_signal: Option<Arc<AtomicBool>>,
+
+    /// ggml threadpool configuration used for generation and batch processing; `None` leaves
+    /// thread management to ggml's own ad-hoc pool
+    pub threadpool: Option<ThreadpoolParams>,
+}
+
+/// CPU threadpool configuration, mirrors upstream's `ggml_threadpool_params`.
+///
+/// Constructing a pool from this config once and reusing it across generations (instead of
+/// spawning threads per decode) and pinning it to physical cores gives measurable
+/// latency/throughput gains on hybrid-core CPUs.
+#[derive(Clone, Debug)]
+pub struct ThreadpoolParams {
+    /// number of threads in the pool
+    pub n_threads: u32,
+
+    /// per-core affinity mask; an empty mask leaves affinity unset
+    pub cpu_mask: Vec<bool>,
+
+    /// scheduling priority for pool threads
+    pub priority: i32,
+
+    /// busy-wait instead of yielding while waiting for work
+    pub poll: bool,
+
+    /// require strict placement onto the cores set in `cpu_mask`
+    pub strict_cpu: bool,
+}
+
+impl ThreadpoolParams {
+    /// Builds a [`ThreadpoolParams`] with no affinity mask set, using `n_threads` worker
+    /// threads and otherwise matching `ggml`'s own defaults.
+    pub fn new(n_threads: u32) -> Self {
+        Self {
+            n_threads,
+            cpu_mask: Vec::new(),
+            priority: 0,
+            poll: false,
+            strict_cpu: false,
+        }
+    }
+}
+
+impl From<&ThreadpoolParams> for ggml_threadpool_params {
+    fn from(value: &ThreadpoolParams) -> Self {
+        let mut cpumask = [false; GGML_MAX_N_THREADS as usize];
+        for (slot, &enabled) in cpumask.iter_mut().zip(value.cpu_mask.iter()) {
+            *slot = enabled;
+        }
+
+        Self {
+            n_threads: value.n_threads as i32,
+            prio: value.priority,
+            poll: value.poll,
+            strict_cpu: value.strict_cpu,
+            mask_specified: !value.cpu_mask.is_empty(),
+            cpumask,
+        }
+    }
+}
+
+#[cfg(test)]
+mod threadpool_params_tests {
+    use super::*;
+
+    #[test]
+    fn empty_mask_is_not_specified() {
+        let raw: ggml_threadpool_params = (&ThreadpoolParams::new(4)).into();
+
+        assert!(!raw.mask_specified);
+        assert!(raw.cpumask.iter().all(|&bit| !bit));
+    }
+
+    #[test]
+    fn scalar_fields_are_carried_over() {
+        let mut params = ThreadpoolParams::new(4);
+        params.priority = 2;
+        params.poll = true;
+        params.strict_cpu = true;
+
+        let raw: ggml_threadpool_params = (&params).into();
+
+        assert_eq!(raw.n_threads, 4);
+        assert_eq!(raw.prio, 2);
+        assert!(raw.poll);
+        assert!(raw.strict_cpu);
+    }
+
+    #[test]
+    fn short_mask_is_packed_into_leading_bits() {
+        let mut params = ThreadpoolParams::new(4);
+        params.cpu_mask = vec![true, false, true];
+
+        let raw: ggml_threadpool_params = (&params).into();
+
+        assert!(raw.mask_specified);
+        assert!(raw.cpumask[0]);
+        assert!(!raw.cpumask[1]);
+        assert!(raw.cpumask[2]);
+        assert!(raw.cpumask[3..].iter().all(|&bit| !bit));
+    }
+
+    #[test]
+    fn overlong_mask_is_truncated_to_ggml_max_threads() {
+        let mut params = ThreadpoolParams::new(4);
+        params.cpu_mask = vec![true; GGML_MAX_N_THREADS as usize + 16];
+
+        let raw: ggml_threadpool_params = (&params).into();
+
+        assert!(raw.mask_specified);
+        assert!(raw.cpumask.iter().all(|&bit| bit));
+    }
+}
+
+/// Trampoline handed to `llama_context_params::abort_callback`; `user_data` is the raw pointer
+/// backing the `Arc<AtomicBool>` stashed in [`SessionParams::abort_signal`].
+unsafe extern "C" fn abort_trampoline(user_data: *mut c_void) -> bool {
+    // SAFETY: only ever installed alongside a matching `abort_callback_data` pointing at a live
+    // `AtomicBool`, kept alive for as long as the session holds onto its `SessionParams`.
+    unsafe { &*(user_data as *const AtomicBool) }.load(Ordering::Relaxed)
 }
 
 impl Default for SessionParams {
@@ -121,6 +306,7 @@ impl Default for SessionParams {
             seed: c_defaults.seed,
             n_ctx: c_defaults.n_ctx,
             n_batch: c_defaults.n_batch,
+            n_ubatch: c_defaults.n_ubatch,
             n_threads: threads,
             n_threads_batch: threads,
             rope_scaling_type: c_defaults.rope_scaling_type,
@@ -136,17 +322,37 @@ impl Default for SessionParams {
             embedding: c_defaults.embedding,
             offload_kqv: c_defaults.offload_kqv,
             pooling: c_defaults.pooling_type.into(),
+            attention_type: c_defaults.attention_type.into(),
             defrag_threshold: c_defaults.defrag_thold,
+            abort_signal: None,
+            threadpool: None,
         }
     }
 }
 
-impl From<SessionParams> for llama_context_params {
-    fn from(value: SessionParams) -> Self {
+impl SessionParams {
+    /// Sets the ggml threadpool configuration used for generation and batch processing.
+    pub fn with_threadpool(mut self, threadpool: ThreadpoolParams) -> Self {
+        self.threadpool = Some(threadpool);
+        self
+    }
+}
+
+impl From<&SessionParams> for llama_context_params {
+    fn from(value: &SessionParams) -> Self {
+        // The physical batch can never exceed the logical batch, nor the context size
+        // (when the latter isn't deferred to the model's own default).
+        let n_ubatch = if value.n_ctx == 0 {
+            value.n_ubatch.min(value.n_batch)
+        } else {
+            value.n_ubatch.min(value.n_batch).min(value.n_ctx)
+        };
+
         Self {
             seed: value.seed,
             n_ctx: value.n_ctx,
             n_batch: value.n_batch,
+            n_ubatch,
             n_threads: value.n_threads,
             n_threads_batch: value.n_threads_batch,
             rope_scaling_type: value.rope_scaling_type,
@@ -166,8 +372,65 @@ impl From<SessionParams> for llama_context_params {
             embedding: value.embedding,
             offload_kqv: value.offload_kqv,
             pooling_type: value.pooling.into(),
-            abort_callback: None,
-            abort_callback_data: null_mut(),
+            attention_type: value.attention_type.into(),
+            abort_callback: value.abort_signal.is_some().then_some(abort_trampoline),
+            abort_callback_data: value
+                .abort_signal
+                .as_ref()
+                .map_or(null_mut(), |flag| Arc::as_ptr(flag) as *mut c_void),
         }
     }
 }
+
+#[cfg(test)]
+mod n_ubatch_clamping_tests {
+    use super::*;
+
+    #[test]
+    fn below_n_batch_and_n_ctx_is_unchanged() {
+        let mut params = SessionParams::default();
+        params.n_ctx = 4096;
+        params.n_batch = 512;
+        params.n_ubatch = 128;
+
+        let cparams: llama_context_params = (&params).into();
+
+        assert_eq!(cparams.n_ubatch, 128);
+    }
+
+    #[test]
+    fn above_n_batch_is_clamped_to_n_batch() {
+        let mut params = SessionParams::default();
+        params.n_ctx = 4096;
+        params.n_batch = 512;
+        params.n_ubatch = 4096;
+
+        let cparams: llama_context_params = (&params).into();
+
+        assert_eq!(cparams.n_ubatch, 512);
+    }
+
+    #[test]
+    fn above_n_ctx_is_clamped_to_n_ctx() {
+        let mut params = SessionParams::default();
+        params.n_ctx = 256;
+        params.n_batch = 2048;
+        params.n_ubatch = 2048;
+
+        let cparams: llama_context_params = (&params).into();
+
+        assert_eq!(cparams.n_ubatch, 256);
+    }
+
+    #[test]
+    fn n_ctx_zero_means_deferred_to_model_and_is_not_clamped_against() {
+        let mut params = SessionParams::default();
+        params.n_ctx = 0;
+        params.n_batch = 512;
+        params.n_ubatch = 512;
+
+        let cparams: llama_context_params = (&params).into();
+
+        assert_eq!(cparams.n_ubatch, 512);
+    }
+}